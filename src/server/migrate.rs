@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use kvproto::raft_serverpb::StoreIdent;
+use raftserver::store::keys;
+use super::engine::KvEngine;
+use super::{Result, other};
+
+/// Copies every key in `src` into `dest`, preserving the `StoreIdent` and all
+/// region state, so the destination directory passes `Node::check_stores`
+/// unchanged.
+///
+/// This is the offline counterpart to switching a node's `KvEngine`
+/// implementation: it lets an operator migrate a store's data directory from
+/// one backend to another (e.g. retiring RocksDB for an LMDB- or
+/// SQLite-backed engine) without going through the raft/store bootstrap
+/// path. The conversion is crash-safe in that the destination's
+/// `store_ident_key` is written last -- a destination directory interrupted
+/// mid-copy simply looks unbootstrapped and is safe to discard and retry.
+pub fn convert_db<S, D>(cluster_id: u64, src: Arc<S>, dest: Arc<D>) -> Result<()>
+    where S: KvEngine,
+          D: KvEngine
+{
+    let ident: StoreIdent = match try!(src.get_msg(&keys::store_ident_key())) {
+        Some(ident) => ident,
+        None => return Err(other("source store has not been bootstrapped".to_owned())),
+    };
+
+    if ident.get_cluster_id() != cluster_id {
+        return Err(other(format!("source store ident {:?} has mismatched cluster id with {}",
+                                 ident,
+                                 cluster_id)));
+    }
+
+    let ident_key = keys::store_ident_key();
+    try!(src.iterate(keys::MIN_KEY, keys::MAX_KEY, |key, value| {
+        if key == &*ident_key {
+            // Written last, once everything else has landed.
+            return Ok(true);
+        }
+        try!(dest.put(key, value));
+        Ok(true)
+    }));
+
+    try!(dest.put_msg(&ident_key, &ident));
+
+    let copied: StoreIdent = match try!(dest.get_msg(&ident_key)) {
+        Some(copied) => copied,
+        None => return Err(other("destination store ident missing after conversion".to_owned())),
+    };
+
+    if copied.get_node_id() != ident.get_node_id() || copied.get_store_id() != ident.get_store_id() {
+        return Err(other(format!("converted store ident {:?} does not round-trip source {:?}",
+                                 copied,
+                                 ident)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::collections::BTreeMap;
+
+    use protobuf::{Message, MessageStatic};
+    use raftserver::store::Peekable;
+    use kvproto::raft_serverpb::StoreIdent;
+
+    use super::convert_db;
+    use super::super::engine::KvEngine;
+    use super::super::Result;
+
+    // An in-memory stand-in for a KvEngine, just enough of one to exercise
+    // convert_db without a real RocksDB (or alternate backend) on disk.
+    #[derive(Default)]
+    struct MapEngine {
+        data: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    }
+
+    impl Peekable for MapEngine {
+        fn get_msg<M: Message + MessageStatic>(&self, key: &[u8]) -> Result<Option<M>> {
+            match self.data.lock().unwrap().get(key) {
+                Some(bytes) => {
+                    let mut m = M::new();
+                    try!(m.merge_from_bytes(bytes));
+                    Ok(Some(m))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    impl KvEngine for MapEngine {
+        fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+            self.data.lock().unwrap().insert(key.to_vec(), value.to_vec());
+            Ok(())
+        }
+
+        fn get_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(self.data.lock().unwrap().get(key).cloned())
+        }
+
+        fn put_msg<M: Message>(&self, key: &[u8], m: &M) -> Result<()> {
+            let bytes = try!(m.write_to_bytes());
+            self.put(key, &bytes)
+        }
+
+        fn delete(&self, key: &[u8]) -> Result<()> {
+            self.data.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        fn write(&self, _wb: ::rocksdb::WriteBatch) -> Result<()> {
+            // convert_db never batches writes, so this is never exercised;
+            // the stub accepts the batch and drops it.
+            Ok(())
+        }
+
+        fn write_batch(&self) -> ::rocksdb::WriteBatch {
+            ::rocksdb::WriteBatch::new()
+        }
+
+        fn iterate<F>(&self, start_key: &[u8], end_key: &[u8], mut f: F) -> Result<()>
+            where F: FnMut(&[u8], &[u8]) -> Result<bool>
+        {
+            for (k, v) in self.data.lock().unwrap().range(start_key.to_vec()..end_key.to_vec()) {
+                if !try!(f(k, v)) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn ident(cluster_id: u64, node_id: u64, store_id: u64) -> StoreIdent {
+        let mut ident = StoreIdent::new();
+        ident.set_cluster_id(cluster_id);
+        ident.set_node_id(node_id);
+        ident.set_store_id(store_id);
+        ident
+    }
+
+    #[test]
+    fn copies_ident_and_data_and_round_trips() {
+        let src = Arc::new(MapEngine::default());
+        src.put_msg(&::raftserver::store::keys::store_ident_key(), &ident(1, 2, 3)).unwrap();
+        src.put(b"region-data", b"value").unwrap();
+
+        let dest = Arc::new(MapEngine::default());
+        convert_db(1, src, dest.clone()).unwrap();
+
+        assert_eq!(dest.get_value(b"region-data").unwrap(), Some(b"value".to_vec()));
+        let copied: StoreIdent = dest.get_msg(&::raftserver::store::keys::store_ident_key())
+                                      .unwrap()
+                                      .unwrap();
+        assert_eq!(copied.get_node_id(), 2);
+        assert_eq!(copied.get_store_id(), 3);
+    }
+
+    #[test]
+    fn refuses_cluster_id_mismatch() {
+        let src = Arc::new(MapEngine::default());
+        src.put_msg(&::raftserver::store::keys::store_ident_key(), &ident(1, 2, 3)).unwrap();
+
+        let dest = Arc::new(MapEngine::default());
+        assert!(convert_db(999, src, dest).is_err());
+    }
+
+    #[test]
+    fn refuses_unbootstrapped_source() {
+        let src = Arc::new(MapEngine::default());
+        let dest = Arc::new(MapEngine::default());
+        assert!(convert_db(1, src, dest).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::thread;
+use std::time::Duration;
 use std::sync::{Arc, RwLock};
 
 use rocksdb::DB;
@@ -9,37 +10,90 @@ use kvproto::raft_serverpb::StoreIdent;
 use kvproto::metapb;
 use raftserver::store::{self, Msg, Store, Config as StoreConfig, keys, Peekable, Transport};
 use super::{Result, other};
+use super::engine::KvEngine;
+use super::encryption::{self, MasterKey, EncryptionInfo};
+use super::repair::{self, RepairWorker};
+use super::placement::Candidate;
 use util::HandyRwLock;
 use super::config::Config;
 use storage::{Storage, Engine, RaftKv};
 
-pub fn create_raft_storage<T, Trans>(node: Node<T, Trans>) -> Result<Storage>
+// How long stop_store waits after deregistering a store's sendch before
+// asking its thread to quit, giving already-in-flight messages a chance to
+// land. See Node::remove_store.
+const STORE_DRAIN_GRACE_MS: u64 = 500;
+
+// Key a store's `EncryptionInfo` record (its wrapped DEK generations) is
+// stored under, in the engine's own keyspace rather than in a
+// `StoreIdent` field -- `StoreIdent`'s schema comes from kvproto, a crate
+// outside this series, so it can't gain an `encryption_info` field here.
+const ENCRYPTION_INFO_KEY: &'static [u8] = b"store_encryption_info";
+
+fn load_encryption_info<E: KvEngine>(engine: &Arc<E>) -> Result<Option<EncryptionInfo>> {
+    match try!(engine.get_value(ENCRYPTION_INFO_KEY)) {
+        Some(bytes) => Ok(Some(try!(EncryptionInfo::decode(&bytes)))),
+        None => Ok(None),
+    }
+}
+
+// Written through a `write` batch, which `EncryptingEngine` passes through
+// unsealed: the record is what the keys are recovered from, so it has to
+// stay readable before any KeyRing is installed. `get_value` returns an
+// unsealed value unchanged, so `load_encryption_info` reads it back as is.
+fn save_encryption_info<E: KvEngine>(engine: &Arc<E>, info: &EncryptionInfo) -> Result<()> {
+    let wb = engine.write_batch();
+    try!(::rocksdb::Writable::put(&wb, ENCRYPTION_INFO_KEY, &info.encode()));
+    engine.write(wb)
+}
+
+/// Builds the `Storage` handle the server hands to the request-serving
+/// layer, wrapping `node` in a `RaftKv`.
+///
+/// Note for callers: `Node::new` is fallible now (it reports a bad master
+/// key instead of panicking), and this function, `Node::new`, and
+/// `Node::start` are generic over `E: KvEngine` to support a non-RocksDB
+/// backend. Server bootstrap needs updating alongside this
+/// request to `try!()` `Node::new`'s result and thread the engine type
+/// parameter through to this call, or it won't link against the new
+/// signatures.
+pub fn create_raft_storage<T, Trans, E>(node: Node<T, Trans, E>) -> Result<Storage>
     where T: PdClient + 'static,
-          Trans: Transport + 'static
+          Trans: Transport + 'static,
+          E: KvEngine
 {
     let engine = box RaftKv::new(node);
     let store = try!(Storage::from_engine(engine));
     Ok(store)
 }
 
-pub struct Node<T: PdClient + 'static, Trans: Transport + 'static> {
+pub struct Node<T: PdClient + 'static, Trans: Transport + 'static, E: KvEngine = DB> {
     cluster_id: u64,
     node: metapb::Node,
     store_cfg: StoreConfig,
     store_handles: HashMap<u64, thread::JoinHandle<()>>,
+    repair_workers: HashMap<u64, RepairWorker>,
 
     pd_client: Arc<RwLock<T>>,
     trans: Arc<RwLock<Trans>>,
+    master_key: Option<Arc<MasterKey>>,
+    // PD/placement metadata -- lives on the server Config, not StoreConfig,
+    // since it's a cluster-layout concern rather than raft tuning.
+    zone: String,
+    capacity: u64,
+    repair_scrub_enabled: bool,
+    repair_scrub_interval: Duration,
+    _marker: ::std::marker::PhantomData<E>,
 }
 
-impl<T, Trans> Node<T, Trans>
+impl<T, Trans, E> Node<T, Trans, E>
     where T: PdClient,
-          Trans: Transport
+          Trans: Transport,
+          E: KvEngine
 {
     pub fn new(cfg: &Config,
                pd_client: Arc<RwLock<T>>,
                trans: Arc<RwLock<Trans>>)
-               -> Node<T, Trans> {
+               -> Result<Node<T, Trans, E>> {
         let mut node = metapb::Node::new();
         node.set_node_id(INVALID_ID);
         if cfg.advertise_addr.is_empty() {
@@ -48,17 +102,29 @@ impl<T, Trans> Node<T, Trans>
             node.set_address(cfg.advertise_addr.clone())
         }
 
-        Node {
+        let master_key = match cfg.master_key.clone() {
+            Some(k) => Some(Arc::new(try!(MasterKey::new(k)))),
+            None => None,
+        };
+
+        Ok(Node {
             cluster_id: cfg.cluster_id,
             node: node,
             store_cfg: cfg.store_cfg.clone(),
             store_handles: HashMap::new(),
+            repair_workers: HashMap::new(),
             pd_client: pd_client,
             trans: trans.clone(),
-        }
+            master_key: master_key,
+            zone: cfg.zone.clone(),
+            capacity: cfg.capacity,
+            repair_scrub_enabled: cfg.repair_scrub_enabled,
+            repair_scrub_interval: cfg.repair_scrub_interval,
+            _marker: ::std::marker::PhantomData,
+        })
     }
 
-    pub fn start(&mut self, engines: Vec<Arc<DB>>) -> Result<()> {
+    pub fn start(&mut self, engines: Vec<Arc<E>>) -> Result<()> {
         assert!(!engines.is_empty());
 
         let bootstrapped = try!(self.pd_client
@@ -88,10 +154,16 @@ impl<T, Trans> Node<T, Trans>
             }
         }
 
+        // The region this node's own bootstrap just created, if any -- the
+        // only region this series knows about without a PD-side lookup, so
+        // it's the only one registered with its store's repair worker
+        // automatically. See `RepairWorker`'s doc comment.
+        let mut first_region: Option<(u64, metapb::Region)> = None;
         if !bootstrapped {
             // cluster is not bootstrapped, and we choose first store to bootstrap
             // first region.
             let region = try!(self.bootstrap_first_region(engines[0].clone(), store_ids[0]));
+            first_region = Some((store_ids[0], region.clone()));
             try!(self.bootstrap_cluster(engines[0].clone(), &store_ids, region));
         }
 
@@ -101,6 +173,13 @@ impl<T, Trans> Node<T, Trans>
 
         for (index, store_id) in store_ids.iter().enumerate() {
             try!(self.start_store(cluster_meta.clone(), *store_id, engines[index].clone()));
+            if let Some((first_store_id, ref region)) = first_region {
+                if first_store_id == *store_id {
+                    if let Some(worker) = self.repair_workers.get(store_id) {
+                        worker.register_region(region.clone());
+                    }
+                }
+            }
             try!(self.pd_client
                      .write()
                      .unwrap()
@@ -121,7 +200,7 @@ impl<T, Trans> Node<T, Trans>
     // check stores, return node id, corresponding store id for the engine.
     // If the store is not bootstrapped, use INVALID_ID.
     // If all the stores are not bootstrapped, the return node id is INVALID_ID.
-    fn check_stores(&self, engines: &[Arc<DB>]) -> Result<(u64, Vec<u64>)> {
+    fn check_stores(&self, engines: &[Arc<E>]) -> Result<(u64, Vec<u64>)> {
         let mut stores: Vec<u64> = Vec::with_capacity(engines.len());
         let mut node_id = INVALID_ID;
 
@@ -139,6 +218,16 @@ impl<T, Trans> Node<T, Trans>
                                          self.cluster_id)));
             }
 
+            // Fails cleanly instead of silently reading ciphertext as plaintext
+            // when an encrypted store is opened without its master key, and
+            // otherwise hands the engine every key generation it needs to
+            // decrypt data written before and after any past rotation.
+            let info = try!(load_encryption_info(engine));
+            let keys = try!(encryption::unseal_from_info(self.master_key.as_ref().map(|k| &**k), info.as_ref()));
+            if let Some(keys) = keys {
+                engine.set_keys(keys);
+            }
+
             if node_id == INVALID_ID {
                 node_id = ident.get_node_id();
             } else if ident.get_node_id() != node_id {
@@ -158,16 +247,68 @@ impl<T, Trans> Node<T, Trans>
         Ok((node_id, stores))
     }
 
-    fn bootstrap_store(&self, engine: Arc<DB>) -> Result<u64> {
+    fn bootstrap_store(&self, engine: Arc<E>) -> Result<u64> {
         let store_id = try!(self.pd_client.wl().alloc_id());
         debug!("alloc store id {} for node {}", store_id, self.id());
 
-        try!(store::bootstrap_store(engine, self.cluster_id, self.id(), store_id));
+        try!(store::bootstrap_store(engine.clone(), self.cluster_id, self.id(), store_id));
+
+        if let Some(ref master_key) = self.master_key {
+            try!(self.seal_store_key(&engine, &**master_key));
+        }
 
         Ok(store_id)
     }
 
-    fn bootstrap_first_region(&self, engine: Arc<DB>, store_id: u64) -> Result<metapb::Region> {
+    // Generates this store's first data-encryption key, records it under
+    // this store's `EncryptionInfo` key (see `ENCRYPTION_INFO_KEY`), and
+    // hands the resulting KeyRing to the engine so the data it writes from
+    // here on is actually sealed rather than just nominally "encrypted".
+    fn seal_store_key(&self, engine: &Arc<E>, master_key: &MasterKey) -> Result<()> {
+        let mut info = EncryptionInfo::default();
+        let keys = try!(encryption::seal_new_key(master_key, &mut info));
+        try!(save_encryption_info(engine, &info));
+        engine.set_keys(keys);
+        Ok(())
+    }
+
+    /// Rotates `store_id`'s data-encryption key: seals a fresh generation
+    /// into its `EncryptionInfo` record, switches the engine to it for new
+    /// writes, and kicks off a throttled background pass re-encrypting
+    /// whatever is still under the previous generation. Data under the old
+    /// key id stays readable throughout, since the engine's KeyRing keeps
+    /// every generation it has ever seen.
+    ///
+    /// This is an operator/ops entry point: nothing in this module calls it
+    /// on its own (there's no scheduled rotation policy here), it's meant to
+    /// be wired to whatever hot-plug/ops surface triggers a manual key
+    /// rotation (e.g. an admin RPC or CLI command).
+    pub fn rotate_store_key(&self, store_id: u64, engine: &Arc<E>) -> Result<()> {
+        let master_key = match self.master_key {
+            Some(ref k) => k,
+            None => return Err(other("cannot rotate: no master key is configured".to_owned())),
+        };
+
+        let mut info = match try!(load_encryption_info(engine)) {
+            Some(info) => info,
+            None => return Err(other(format!("store {} is not encrypted, nothing to rotate", store_id))),
+        };
+        let old_key_id = info.active_key_id();
+
+        let keys = try!(encryption::seal_new_key(master_key, &mut info));
+        try!(save_encryption_info(engine, &info));
+        engine.set_keys(keys);
+
+        let engine = engine.clone();
+        thread::spawn(move || {
+            if let Err(e) = engine.reencrypt_from(old_key_id, Duration::from_millis(100)) {
+                error!("store {} re-encrypt from key {} err {:?}", store_id, old_key_id, e);
+            }
+        });
+        Ok(())
+    }
+
+    fn bootstrap_first_region(&self, engine: Arc<E>, store_id: u64) -> Result<metapb::Region> {
         let region_id = try!(self.pd_client.wl().alloc_id());
         debug!("alloc first region id {} for cluster {}, node {}, store {}",
                region_id,
@@ -190,6 +331,26 @@ impl<T, Trans> Node<T, Trans>
         store
     }
 
+    /// Builds this node's view of `store_id` as a placement candidate, for
+    /// whatever drives `placement::choose_replica_stores` (the cluster-level
+    /// region scheduler) when it's choosing where to put a new region's
+    /// replicas.
+    ///
+    /// This is where the node's zone/capacity config is used;
+    /// `new_store_meta` does not record it: `metapb::Store` in the baseline this series builds
+    /// against only carries `node_id`/`store_id` (no label or capacity
+    /// fields), and extending it would be a kvproto schema change outside
+    /// this series, so the zone/weight PD needs for placement are read
+    /// straight off `Node`'s own config here rather than round-tripped
+    /// through a store meta PD stores and hands back.
+    pub fn as_candidate(&self, store_id: u64) -> Candidate {
+        Candidate {
+            store_id: store_id,
+            zone: self.zone.clone(),
+            weight: self.capacity.min(u32::max_value() as u64) as u32,
+        }
+    }
+
     fn new_store_metas(&self, store_ids: &[u64]) -> Vec<metapb::Store> {
         let mut stores: Vec<metapb::Store> = Vec::with_capacity(store_ids.len());
         for store_id in store_ids {
@@ -199,7 +360,7 @@ impl<T, Trans> Node<T, Trans>
     }
 
     fn bootstrap_cluster(&mut self,
-                         engine: Arc<DB>,
+                         engine: Arc<E>,
                          store_ids: &[u64],
                          region: metapb::Region)
                          -> Result<()> {
@@ -222,7 +383,41 @@ impl<T, Trans> Node<T, Trans>
         }
     }
 
-    fn start_store(&mut self, meta: metapb::Cluster, store_id: u64, engine: Arc<DB>) -> Result<()> {
+    /// Brings a new store online without restarting the node: bootstraps
+    /// `engine` (allocating a store id from PD the same way `start` does),
+    /// registers it with the transport, and spawns its store thread. Use
+    /// this to add a disk to a node that is already serving other stores.
+    pub fn add_store(&mut self, engine: Arc<E>) -> Result<u64> {
+        let store_id = try!(self.bootstrap_store(engine.clone()));
+        let cluster_meta = try!(self.pd_client.rl().get_cluster_meta(self.cluster_id));
+        try!(self.start_store(cluster_meta, store_id, engine));
+        try!(self.pd_client
+                 .write()
+                 .unwrap()
+                 .put_store(self.cluster_id, self.new_store_meta(store_id)));
+        Ok(store_id)
+    }
+
+    /// Stops a running store's local thread: deregisters it from the
+    /// transport so no new raft traffic handed to *this node* is routed to
+    /// it, gives whatever was already in flight a grace period to land,
+    /// then sends `Msg::Quit` and joins its thread -- while the node keeps
+    /// serving its other stores. Use this to retire a failing disk without
+    /// a full process restart.
+    ///
+    /// Scope: this is local-only. It does not transfer away leaderships,
+    /// and it does not tell PD anything -- the store is not marked
+    /// offline or tombstone, so PD and the rest of the cluster keep
+    /// treating it as a live destination (routing raft messages and new
+    /// replicas to it) until something else updates its PD state. Pair
+    /// this with an external offline/tombstone transition in PD before
+    /// relying on the rest of the cluster to stop using the store; full
+    /// decommissioning is out of scope here.
+    pub fn remove_store(&mut self, store_id: u64) -> Result<()> {
+        self.stop_store(store_id)
+    }
+
+    fn start_store(&mut self, meta: metapb::Cluster, store_id: u64, engine: Arc<E>) -> Result<()> {
         if self.store_handles.contains_key(&store_id) {
             return Err(other(format!("duplicated store id {}", store_id)));
         }
@@ -233,7 +428,7 @@ impl<T, Trans> Node<T, Trans>
         let mut store = try!(Store::new(&mut event_loop,
                                         meta,
                                         cfg,
-                                        engine,
+                                        engine.clone(),
                                         self.trans.clone(),
                                         pd_client));
         let ch = store.get_sendch();
@@ -246,6 +441,10 @@ impl<T, Trans> Node<T, Trans>
         });
 
         self.store_handles.insert(store_id, h);
+        if self.repair_scrub_enabled {
+            let worker = try!(RepairWorker::start(store_id, engine, self.repair_scrub_interval));
+            self.repair_workers.insert(store_id, worker);
+        }
         Ok(())
     }
 
@@ -261,19 +460,46 @@ impl<T, Trans> Node<T, Trans>
             return Err(other(format!("store {} thread has already gone", store_id)));
         }
 
+        // Best-effort drain: now that no new messages are routed to this
+        // store, give whatever was already queued on its sendch a grace
+        // period to be processed before asking the thread to quit.
+        thread::sleep(Duration::from_millis(STORE_DRAIN_GRACE_MS));
+
         try!(ch.unwrap().send(Msg::Quit));
 
         if let Err(e) = h.unwrap().join() {
             return Err(other(format!("join store {} thread err {:?}", store_id, e)));
         }
 
+        if let Some(mut worker) = self.repair_workers.remove(&store_id) {
+            try!(worker.stop());
+        }
+
         Ok(())
     }
+
+    /// Triggers an immediate full scrub of `store_id`'s regions, returning
+    /// once the request is sent. Poll `repair_progress` to watch it run to
+    /// completion.
+    pub fn trigger_repair_scrub(&self, store_id: u64) -> Result<()> {
+        match self.repair_workers.get(&store_id) {
+            Some(worker) => worker.trigger_scrub(),
+            None => Err(other(format!("no repair worker for store {}", store_id))),
+        }
+    }
+
+    pub fn repair_progress(&self, store_id: u64) -> Result<repair::Progress> {
+        match self.repair_workers.get(&store_id) {
+            Some(worker) => Ok(worker.progress()),
+            None => Err(other(format!("no repair worker for store {}", store_id))),
+        }
+    }
 }
 
-impl<T, Trans> Drop for Node<T, Trans>
+impl<T, Trans, E> Drop for Node<T, Trans, E>
     where T: PdClient,
-          Trans: Transport + 'static
+          Trans: Transport + 'static,
+          E: KvEngine
 {
     fn drop(&mut self) {
         let ids: Vec<u64> = self.store_handles.keys().cloned().collect();
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, VecDeque};
+
+/// A placement candidate: a store PD could assign a new replica to, along
+/// with its node's locality/capacity config (see `Node::as_candidate`).
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub store_id: u64,
+    pub zone: String,
+    pub weight: u32,
+}
+
+/// Chooses `replica_count` stores out of `candidates` to host a new
+/// region's peers.
+///
+/// Builds a flow network -- source -> store (capacity = store weight, at
+/// least 1),
+/// store -> its zone's aggregation node (capacity 1, since one store never
+/// hosts two replicas of the same region), zone -> sink (capacity capped so
+/// no single zone can take more than its fair share) -- and reads off
+/// which source->store edges carry flow once the max flow is computed.
+/// When at least `replica_count`
+/// distinct zones are available each zone's cap is 1, so every replica
+/// lands in a different zone; otherwise the cap is spread proportionally to
+/// `replica_count` so placement still succeeds, just without full zone
+/// isolation.
+///
+/// Invariant: the result never contains the same store twice, and has at
+/// most `replica_count` entries (fewer if `candidates` can't support that
+/// many: too few stores, or too few outside zones that have hit their cap).
+/// Weight only orders the candidates; it never limits how many are chosen.
+pub fn choose_replica_stores(candidates: &[Candidate], replica_count: usize) -> Vec<u64> {
+    if replica_count == 0 || candidates.is_empty() {
+        return Vec::new();
+    }
+
+    let mut zones: Vec<String> = candidates.iter().map(|c| c.zone.clone()).collect();
+    zones.sort();
+    zones.dedup();
+
+    let zone_cap = if zones.len() >= replica_count {
+        1
+    } else {
+        // Not enough zones to give every replica its own: spread as evenly
+        // as the replication factor allows instead of refusing placement.
+        (replica_count + zones.len() - 1) / zones.len()
+    };
+
+    // Node ids: 0 = source, 1..=n = stores (by index into `candidates`),
+    // n+1..=n+z = zones (by index into `zones`), n+z+1 = sink.
+    let n = candidates.len();
+    let z = zones.len();
+    let source = 0;
+    let sink = n + z + 1;
+    let mut graph = FlowGraph::new(sink + 1);
+
+    let zone_index: HashMap<&str, usize> = zones.iter()
+                                                  .enumerate()
+                                                  .map(|(i, zone)| (zone.as_str(), i))
+                                                  .collect();
+
+    // Prefer higher-weight stores by adding them first: Edmonds-Karp's BFS
+    // walks edges in insertion order, so earlier edges win ties for which
+    // augmenting path gets found first.
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| candidates[b].weight.cmp(&candidates[a].weight));
+
+    for &i in &order {
+        let store_node = 1 + i;
+        let zone_node = 1 + n + zone_index[candidates[i].zone.as_str()];
+        graph.add_edge(source, store_node, candidates[i].weight.max(1) as i64);
+        graph.add_edge(store_node, zone_node, 1);
+    }
+    for zi in 0..z {
+        graph.add_edge(1 + n + zi, sink, zone_cap as i64);
+    }
+
+    graph.max_flow(source, sink);
+
+    let mut chosen = Vec::new();
+    for &i in &order {
+        if chosen.len() == replica_count {
+            break;
+        }
+        let store_node = 1 + i;
+        if graph.edge_has_flow(source, store_node) {
+            chosen.push(candidates[i].store_id);
+        }
+    }
+
+    chosen
+}
+
+// A minimal Edmonds-Karp max-flow over an adjacency-list graph with
+// directed, capacitated edges. Kept local to this module: placement is the
+// only thing in the store/raft path that needs max-flow.
+struct FlowGraph {
+    // to, capacity, index of the reverse edge
+    edges: Vec<Vec<(usize, i64, usize)>>,
+    flow: HashMap<(usize, usize), i64>,
+}
+
+impl FlowGraph {
+    fn new(num_nodes: usize) -> FlowGraph {
+        FlowGraph {
+            edges: vec![Vec::new(); num_nodes],
+            flow: HashMap::new(),
+        }
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, capacity: i64) {
+        let fwd = self.edges[from].len();
+        let rev = self.edges[to].len();
+        self.edges[from].push((to, capacity, rev));
+        self.edges[to].push((from, 0, fwd));
+    }
+
+    fn max_flow(&mut self, source: usize, sink: usize) -> i64 {
+        let mut total = 0;
+        loop {
+            let (parent, found) = self.bfs(source, sink);
+            if !found {
+                break;
+            }
+
+            let mut bottleneck = i64::max_value();
+            let mut v = sink;
+            while v != source {
+                let (u, edge_idx) = parent[v];
+                let (_, cap, _) = self.edges[u][edge_idx];
+                bottleneck = bottleneck.min(cap);
+                v = u;
+            }
+
+            let mut v = sink;
+            while v != source {
+                let (u, edge_idx) = parent[v];
+                let (to, cap, rev) = self.edges[u][edge_idx];
+                self.edges[u][edge_idx] = (to, cap - bottleneck, rev);
+                let (rto, rcap, rrev) = self.edges[to][rev];
+                self.edges[to][rev] = (rto, rcap + bottleneck, rrev);
+                *self.flow.entry((u, to)).or_insert(0) += bottleneck;
+                v = u;
+            }
+
+            total += bottleneck;
+        }
+        total
+    }
+
+    fn bfs(&self, source: usize, sink: usize) -> (Vec<(usize, usize)>, bool) {
+        let mut parent = vec![(0usize, 0usize); self.edges.len()];
+        let mut visited = vec![false; self.edges.len()];
+        visited[source] = true;
+
+        let mut queue = VecDeque::new();
+        queue.push_back(source);
+
+        while let Some(u) = queue.pop_front() {
+            if u == sink {
+                return (parent, true);
+            }
+            for (idx, &(to, cap, _)) in self.edges[u].iter().enumerate() {
+                if cap > 0 && !visited[to] {
+                    visited[to] = true;
+                    parent[to] = (u, idx);
+                    queue.push_back(to);
+                }
+            }
+        }
+
+        (parent, false)
+    }
+
+    fn edge_has_flow(&self, from: usize, to: usize) -> bool {
+        self.flow.get(&(from, to)).cloned().unwrap_or(0) > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn candidate(store_id: u64, zone: &str, weight: u32) -> Candidate {
+        Candidate {
+            store_id: store_id,
+            zone: zone.to_owned(),
+            weight: weight,
+        }
+    }
+
+    #[test]
+    fn spreads_across_distinct_zones_when_enough_are_available() {
+        let candidates = vec![candidate(1, "z1", 1), candidate(2, "z2", 1), candidate(3, "z3", 1)];
+        let chosen = choose_replica_stores(&candidates, 3);
+
+        assert_eq!(chosen.len(), 3);
+        let zones: HashSet<&str> = chosen.iter()
+                                          .map(|id| candidates.iter().find(|c| c.store_id == *id).unwrap().zone.as_str())
+                                          .collect();
+        assert_eq!(zones.len(), 3);
+    }
+
+    #[test]
+    fn never_places_two_replicas_on_the_same_store() {
+        let candidates = vec![candidate(1, "z1", 5), candidate(2, "z1", 1), candidate(3, "z2", 1)];
+        let chosen = choose_replica_stores(&candidates, 2);
+
+        let unique: HashSet<u64> = chosen.iter().cloned().collect();
+        assert_eq!(unique.len(), chosen.len());
+    }
+
+    #[test]
+    fn falls_back_to_weight_spread_when_zones_are_scarce() {
+        // Only one zone for 3 replicas: can't isolate by zone, but should
+        // still place as many replicas as there are distinct stores.
+        let candidates = vec![candidate(1, "z1", 3), candidate(2, "z1", 2), candidate(3, "z1", 1)];
+        let chosen = choose_replica_stores(&candidates, 3);
+
+        assert_eq!(chosen.len(), 3);
+    }
+
+    #[test]
+    fn caps_at_available_candidates() {
+        let candidates = vec![candidate(1, "z1", 1), candidate(2, "z2", 1)];
+        let chosen = choose_replica_stores(&candidates, 5);
+
+        assert_eq!(chosen.len(), 2);
+    }
+
+    #[test]
+    fn zero_weight_store_is_still_usable() {
+        let candidates = vec![candidate(1, "z1", 0)];
+        let chosen = choose_replica_stores(&candidates, 1);
+
+        assert_eq!(chosen, vec![1]);
+    }
+
+    #[test]
+    fn empty_candidates_returns_empty() {
+        assert!(choose_replica_stores(&[], 3).is_empty());
+    }
+}
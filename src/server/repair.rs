@@ -0,0 +1,378 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use kvproto::metapb;
+use raftserver::store::keys;
+use super::engine::KvEngine;
+use super::{Result, other};
+
+/// Commands accepted by a running `RepairWorker`.
+pub enum RepairMsg {
+    /// Kick off (or re-kick, if one is already running) a full scrub of
+    /// every registered region, ahead of its next scheduled tick.
+    Scrub,
+    Quit,
+}
+
+/// A region whose data checksum differs from the one this worker recorded
+/// the last time it scrubbed that region.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub region_id: u64,
+    pub previous: u64,
+    pub found: u64,
+}
+
+/// A point-in-time view of a scrub, polled by whatever is monitoring it.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub running: bool,
+    pub scanned_regions: u64,
+    pub total_regions: u64,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Background worker that periodically recomputes a checksum over each of
+/// its registered regions' data, comparing it against the checksum the
+/// worker itself recorded on the previous scrub.
+///
+/// This is *not* a check against the raft log or apply state: nothing in
+/// this store persists a per-region data checksum at apply time, so there
+/// is no such value to compare against. What this can honestly promise is
+/// drift detection -- if a region's data changes between two scrubs
+/// without this node's own raft/store code going through it (e.g. disk bit
+/// rot, or something writing to the engine out of band), the next scrub
+/// notices. A region's first scrub only records a baseline and never
+/// reports a mismatch.
+///
+/// Regions are scrubbed only once handed to `register_region` -- this
+/// store's engine has no region-meta keyspace this worker can scan to
+/// discover them on its own, so whatever calls `RepairWorker::start` is
+/// responsible for registering each region it knows about (and
+/// unregistering one that's moved away).
+pub struct RepairWorker {
+    ch: Sender<RepairMsg>,
+    regions: Arc<Mutex<Vec<metapb::Region>>>,
+    progress: Arc<RwLock<Progress>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl RepairWorker {
+    /// `interval` is how long the worker waits for a command before it
+    /// scrubs on its own, and also the pause after each region within a
+    /// scrub, so one scrub of n regions takes about n * `interval`;
+    /// `trigger_scrub` can still kick one off early. Rejects a zero interval rather than
+    /// starting a worker that would immediately busy-loop scrubbing
+    /// (`recv_timeout(0)` returns `Timeout` right away).
+    pub fn start<E: KvEngine>(store_id: u64, engine: Arc<E>, interval: Duration) -> Result<RepairWorker> {
+        if interval == Duration::new(0, 0) {
+            return Err(other(format!("store {}: repair scrub interval must be non-zero", store_id)));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let progress = Arc::new(RwLock::new(Progress::default()));
+        let worker_progress = progress.clone();
+        let regions = Arc::new(Mutex::new(Vec::new()));
+        let worker_regions = regions.clone();
+
+        let handle = thread::spawn(move || {
+            loop {
+                match rx.recv_timeout(interval) {
+                    Ok(RepairMsg::Quit) => break,
+                    Ok(RepairMsg::Scrub) => scrub(store_id, &engine, &worker_regions, interval, &worker_progress),
+                    Err(RecvTimeoutError::Timeout) => {
+                        scrub(store_id, &engine, &worker_regions, interval, &worker_progress)
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+
+        Ok(RepairWorker {
+            ch: tx,
+            regions: regions,
+            progress: progress,
+            handle: Some(handle),
+        })
+    }
+
+    /// Adds a region to the set this worker scrubs. Safe to call for a
+    /// region that's already registered; it will just be scrubbed twice in
+    /// the same pass.
+    pub fn register_region(&self, region: metapb::Region) {
+        self.regions.lock().unwrap().push(region);
+    }
+
+    pub fn unregister_region(&self, region_id: u64) {
+        self.regions.lock().unwrap().retain(|r| r.get_region_id() != region_id);
+    }
+
+    pub fn trigger_scrub(&self) -> Result<()> {
+        try!(self.ch.send(RepairMsg::Scrub));
+        Ok(())
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress.read().unwrap().clone()
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        try!(self.ch.send(RepairMsg::Quit));
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+        Ok(())
+    }
+}
+
+fn scrub<E: KvEngine>(store_id: u64,
+                       engine: &Arc<E>,
+                       regions: &Arc<Mutex<Vec<metapb::Region>>>,
+                       throttle: Duration,
+                       progress: &Arc<RwLock<Progress>>) {
+    let regions = regions.lock().unwrap().clone();
+
+    {
+        let mut p = progress.write().unwrap();
+        p.running = true;
+        p.scanned_regions = 0;
+        p.total_regions = regions.len() as u64;
+        p.mismatches.clear();
+    }
+
+    for region in &regions {
+        if let Some(mismatch) = checksum_region(engine, region) {
+            warn!("store {} region {} checksum drifted since last scrub: was {}, now {}",
+                  store_id,
+                  mismatch.region_id,
+                  mismatch.previous,
+                  mismatch.found);
+            progress.write().unwrap().mismatches.push(mismatch);
+        }
+        progress.write().unwrap().scanned_regions += 1;
+        thread::sleep(throttle);
+    }
+
+    progress.write().unwrap().running = false;
+}
+
+// Recomputes a region's data checksum and compares it against whatever this
+// worker recorded for that region on its previous scrub (stored under our
+// own key, not the raft apply state -- this store doesn't record a
+// per-region data checksum anywhere at apply time). The first scrub of a
+// region has nothing to compare against, so it just records a baseline.
+fn checksum_region<E: KvEngine>(engine: &Arc<E>, region: &metapb::Region) -> Option<Mismatch> {
+    let region_id = region.get_region_id();
+    let start = keys::data_key(region.get_start_key());
+    let end = if region.get_end_key().is_empty() {
+        keys::MAX_KEY.to_vec()
+    } else {
+        keys::data_key(region.get_end_key())
+    };
+
+    let mut found = FNV_OFFSET;
+    let scanned = engine.iterate(&start, &end, |k, v| {
+        found = fnv1a(found, k);
+        found = fnv1a(found, v);
+        Ok(true)
+    });
+    if scanned.is_err() {
+        return None;
+    }
+
+    let baseline_key = baseline_key(region_id);
+    let previous = match engine.get_value(&baseline_key) {
+        Ok(Some(bytes)) => decode_checksum(&bytes),
+        _ => None,
+    };
+
+    let _ = engine.put(&baseline_key, &encode_checksum(found));
+
+    match previous {
+        Some(previous) if previous != found => {
+            Some(Mismatch {
+                region_id: region_id,
+                previous: previous,
+                found: found,
+            })
+        }
+        _ => None,
+    }
+}
+
+// This worker's own record of a region's last-observed checksum, kept
+// outside the raft/store keyspace entirely so it can't collide with
+// anything the store writes.
+fn baseline_key(region_id: u64) -> Vec<u8> {
+    let mut key = b"repair_checksum_".to_vec();
+    key.extend_from_slice(&encode_checksum(region_id));
+    key
+}
+
+fn encode_checksum(v: u64) -> [u8; 8] {
+    let mut buf = [0u8; 8];
+    for i in 0..8 {
+        buf[i] = (v >> (8 * i)) as u8;
+    }
+    buf
+}
+
+fn decode_checksum(buf: &[u8]) -> Option<u64> {
+    if buf.len() != 8 {
+        return None;
+    }
+    let mut v = 0u64;
+    for i in 0..8 {
+        v |= (buf[i] as u64) << (8 * i);
+    }
+    Some(v)
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use protobuf::{Message, MessageStatic};
+    use raftserver::store::Peekable;
+    use kvproto::metapb;
+
+    use super::*;
+    use super::super::engine::KvEngine;
+    use super::super::Result;
+
+    #[derive(Default)]
+    struct MapEngine {
+        data: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    }
+
+    impl Peekable for MapEngine {
+        fn get_msg<M: Message + MessageStatic>(&self, key: &[u8]) -> Result<Option<M>> {
+            match self.data.lock().unwrap().get(key) {
+                Some(bytes) => {
+                    let mut m = M::new();
+                    try!(m.merge_from_bytes(bytes));
+                    Ok(Some(m))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    impl KvEngine for MapEngine {
+        fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+            self.data.lock().unwrap().insert(key.to_vec(), value.to_vec());
+            Ok(())
+        }
+
+        fn get_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(self.data.lock().unwrap().get(key).cloned())
+        }
+
+        fn put_msg<M: Message>(&self, key: &[u8], m: &M) -> Result<()> {
+            let bytes = try!(m.write_to_bytes());
+            self.put(key, &bytes)
+        }
+
+        fn delete(&self, key: &[u8]) -> Result<()> {
+            self.data.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        fn write(&self, _wb: ::rocksdb::WriteBatch) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_batch(&self) -> ::rocksdb::WriteBatch {
+            ::rocksdb::WriteBatch::new()
+        }
+
+        fn iterate<F>(&self, start_key: &[u8], end_key: &[u8], mut f: F) -> Result<()>
+            where F: FnMut(&[u8], &[u8]) -> Result<bool>
+        {
+            for (k, v) in self.data.lock().unwrap().range(start_key.to_vec()..end_key.to_vec()) {
+                if !try!(f(k, v)) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn region(id: u64, start: &[u8], end: &[u8]) -> metapb::Region {
+        let mut region = metapb::Region::new();
+        region.set_region_id(id);
+        region.set_start_key(start.to_vec());
+        region.set_end_key(end.to_vec());
+        region
+    }
+
+    #[test]
+    fn first_scrub_of_a_region_only_records_a_baseline() {
+        let engine = Arc::new(MapEngine::default());
+        engine.put(b"zdata1", b"hello").unwrap();
+
+        let mismatch = checksum_region(&engine, &region(1, b"", b""));
+        assert!(mismatch.is_none());
+    }
+
+    #[test]
+    fn second_scrub_flags_drift_since_the_first() {
+        let engine = Arc::new(MapEngine::default());
+        engine.put(b"zdata1", b"hello").unwrap();
+        assert!(checksum_region(&engine, &region(1, b"", b"")).is_none());
+
+        engine.put(b"zdata1", b"goodbye").unwrap();
+        let mismatch = checksum_region(&engine, &region(1, b"", b"")).unwrap();
+        assert_eq!(mismatch.region_id, 1);
+        assert_ne!(mismatch.previous, mismatch.found);
+    }
+
+    #[test]
+    fn unchanged_region_never_flags_after_the_baseline() {
+        let engine = Arc::new(MapEngine::default());
+        engine.put(b"zdata1", b"hello").unwrap();
+        assert!(checksum_region(&engine, &region(1, b"", b"")).is_none());
+        assert!(checksum_region(&engine, &region(1, b"", b"")).is_none());
+    }
+
+    #[test]
+    fn rejects_zero_interval() {
+        let engine = Arc::new(MapEngine::default());
+        assert!(RepairWorker::start(1, engine, Duration::new(0, 0)).is_err());
+    }
+
+    #[test]
+    fn scrub_scans_only_registered_regions() {
+        let engine = Arc::new(MapEngine::default());
+        engine.put(b"zdata1", b"hello").unwrap();
+        let worker = RepairWorker::start(1, engine, Duration::from_secs(3600)).unwrap();
+
+        assert_eq!(worker.progress().total_regions, 0);
+        worker.register_region(region(1, b"", b""));
+        worker.trigger_scrub().unwrap();
+
+        // Give the worker thread a moment to pick up the scrub.
+        for _ in 0..100 {
+            if worker.progress().scanned_regions > 0 {
+                break;
+            }
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(worker.progress().total_regions, 1);
+    }
+}